@@ -1,12 +1,15 @@
 //! The configuration structure for the VM.
 //! The `AxVMCrateConfig` is generated from toml file, and then converted to `AxVMConfig` for the VM creation.
 
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 
 use axaddrspace::GuestPhysAddr;
 use axdevice_base::EmulatedDeviceConfig;
-use axerrno::AxResult;
+use axerrno::{ax_err, AxResult};
+
+use crate::vm::{VM_ASPACE_BASE, VM_ASPACE_SIZE};
 
 /// A part of `AxVCpuConfig`, which represents an architecture-dependent `VCpu`.
 ///
@@ -64,6 +67,8 @@ pub struct VMImageConfig {
     pub dtb_load_gpa: Option<GuestPhysAddr>,
     /// The load address in GPA for the ramdisk image, `None` if not used.
     pub ramdisk_load_gpa: Option<GuestPhysAddr>,
+    /// The maximum size in bytes of a single image loaded into the VM, `None` if unlimited.
+    pub max_image_size: Option<usize>,
 }
 
 /// A part of `AxVMCrateConfig`, which represents a `VM`.
@@ -79,7 +84,9 @@ pub struct AxVMConfig {
     cpu_config: AxVCpuConfig,
     image_config: VMImageConfig,
     memory_regions: Vec<VmMemConfig>,
+    trap_null_page: bool,
     emu_devices: Vec<EmulatedDeviceConfig>,
+    max_mmio_regions: Option<usize>,
 }
 
 impl From<AxVMCrateConfig> for AxVMConfig {
@@ -100,9 +107,12 @@ impl From<AxVMCrateConfig> for AxVMConfig {
                 bios_load_gpa: cfg.bios_load_addr.map(GuestPhysAddr::from),
                 dtb_load_gpa: cfg.dtb_load_addr.map(GuestPhysAddr::from),
                 ramdisk_load_gpa: cfg.ramdisk_load_addr.map(GuestPhysAddr::from),
+                max_image_size: cfg.max_image_size,
             },
             memory_regions: cfg.memory_regions,
+            trap_null_page: cfg.trap_null_page,
             emu_devices: cfg.emu_devices,
+            max_mmio_regions: cfg.max_mmio_regions,
         }
     }
 }
@@ -144,6 +154,115 @@ impl AxVMConfig {
         vcpu_pcpu_tuples
     }
 
+    /// Validates the configuration, returning a descriptive error on the first problem found:
+    ///
+    /// - `phys_cpu_ids` and `phys_cpu_sets` must provide one entry per vCpu if set,
+    ///   and physical cpu ids must be unique;
+    /// - memory regions must not be empty;
+    /// - memory regions and emulated device regions must lie within the VM's guest physical
+    ///   address space, and must not overlap each other;
+    /// - the number of emulated devices must not exceed `max_mmio_regions`.
+    pub fn validate(&self) -> AxResult {
+        self.check_vcpu_phys_ids()?;
+
+        if let Some(i) = self.memory_regions.iter().position(|r| r.size == 0) {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "memory region {} at {:#x} is empty",
+                    i, self.memory_regions[i].gpa
+                )
+            );
+        }
+
+        let regions = self
+            .memory_regions
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (format!("memory region {}", i), r.gpa, r.size))
+            .chain(
+                self.emu_devices
+                    .iter()
+                    .map(|d| (format!("device '{}'", d.name), d.base_gpa, d.length)),
+            )
+            .collect::<Vec<_>>();
+        for (i, (name, gpa, size)) in regions.iter().enumerate() {
+            let aspace = VM_ASPACE_BASE..=VM_ASPACE_BASE + VM_ASPACE_SIZE;
+            let in_range = gpa
+                .checked_add(*size)
+                .is_some_and(|end| aspace.contains(gpa) && aspace.contains(&end));
+            if !in_range {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "{} [{:#x}, +{:#x}) exceeds the guest physical address space",
+                        name, gpa, size
+                    )
+                );
+            }
+            for (other_name, other_gpa, other_size) in &regions[..i] {
+                if *gpa < other_gpa + other_size && *other_gpa < gpa + size {
+                    return ax_err!(
+                        InvalidInput,
+                        format!(
+                            "{} [{:#x}, +{:#x}) overlaps {} [{:#x}, +{:#x})",
+                            name, gpa, size, other_name, other_gpa, other_size
+                        )
+                    );
+                }
+            }
+        }
+
+        if let Some(max) = self.max_mmio_regions {
+            if self.emu_devices.len() > max {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "{} emulated devices exceed the limit of {} MMIO regions",
+                        self.emu_devices.len(),
+                        max
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `phys_cpu_ids` and `phys_cpu_sets`, if set, provide exactly one entry per vCpu,
+    /// and that no physical cpu id is assigned to more than one vCpu.
+    fn check_vcpu_phys_ids(&self) -> AxResult {
+        if let Some(phys_cpu_sets) = &self.phys_cpu_sets {
+            if phys_cpu_sets.len() != self.cpu_num {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "phys_cpu_sets has {} entries, but cpu_num is {}",
+                        phys_cpu_sets.len(),
+                        self.cpu_num
+                    )
+                );
+            }
+        }
+        if let Some(phys_cpu_ids) = &self.phys_cpu_ids {
+            if phys_cpu_ids.len() != self.cpu_num {
+                return ax_err!(
+                    InvalidInput,
+                    format!(
+                        "phys_cpu_ids has {} entries, but cpu_num is {}",
+                        phys_cpu_ids.len(),
+                        self.cpu_num
+                    )
+                );
+            }
+            for (i, id) in phys_cpu_ids.iter().enumerate() {
+                if phys_cpu_ids[..i].contains(id) {
+                    return ax_err!(InvalidInput, format!("duplicate CPU id {:#x}", id));
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Returns configurations related to VM image load addresses.
     pub fn image_config(&self) -> &VMImageConfig {
         &self.image_config
@@ -166,10 +285,21 @@ impl AxVMConfig {
         &self.memory_regions
     }
 
+    /// Returns whether the first page at GPA 0 is left unmapped to trap guest null pointer accesses.
+    pub fn trap_null_page(&self) -> bool {
+        self.trap_null_page
+    }
+
     /// Returns configurations related to VM emulated devices.
     pub fn emu_devices(&self) -> &Vec<EmulatedDeviceConfig> {
         &self.emu_devices
     }
+
+    /// Returns the maximum number of MMIO regions, including emulated devices
+    /// and inline MMIO handlers, the VM may have, `None` if unlimited.
+    pub fn max_mmio_regions(&self) -> Option<usize> {
+        self.max_mmio_regions
+    }
 }
 
 /// A part of `AxVMConfig`, which represents a memory region.
@@ -181,6 +311,10 @@ pub struct VmMemConfig {
     pub size: usize,
     /// The mappings flags of the memory region, refers to `MappingFlags` provided by `axaddrspace`.
     pub flags: usize,
+    /// Whether the backing pages of a ram region are allocated on demand when the guest first
+    /// touches them, instead of all at VM creation. Default is `false`.
+    #[serde(default)]
+    pub lazy: bool,
 }
 
 /// The configuration structure for the guest VM serialized from a toml file provided by user,
@@ -233,14 +367,24 @@ pub struct AxVMCrateConfig {
     pub ramdisk_load_addr: Option<usize>,
     /// The location of the image, default is 'fs'.
     pub image_location: Option<String>,
+    /// The maximum size in bytes of a single image, `None` if unlimited.
+    pub max_image_size: Option<usize>,
 
     disk_path: Option<String>,
 
     /// Memory Information
     memory_regions: Vec<VmMemConfig>,
+    /// Whether to leave the first page at GPA 0 unmapped,
+    /// so that guest null pointer accesses fault instead of silently hitting memory.
+    /// Default is `false`.
+    #[serde(default)]
+    trap_null_page: bool,
     /// Emu device Information
     /// Todo: passthrough devices
     emu_devices: Vec<EmulatedDeviceConfig>,
+    /// The maximum number of MMIO regions, including emulated devices and inline MMIO handlers,
+    /// `None` if unlimited.
+    max_mmio_regions: Option<usize>,
 }
 
 impl AxVMCrateConfig {
@@ -253,3 +397,139 @@ impl AxVMCrateConfig {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use axerrno::AxError;
+
+    fn config_with_cpus(
+        cpu_num: usize,
+        phys_cpu_ids: Option<Vec<usize>>,
+        phys_cpu_sets: Option<Vec<usize>>,
+    ) -> AxVMConfig {
+        AxVMConfig {
+            cpu_num,
+            phys_cpu_ids,
+            phys_cpu_sets,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn vcpu_phys_ids_valid() {
+        assert_eq!(config_with_cpus(2, None, None).validate(), Ok(()));
+        assert_eq!(
+            config_with_cpus(2, Some(vec![0x100, 0x101]), Some(vec![0x1, 0x2])).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn vcpu_affinities_pcpu_ids() {
+        let config = config_with_cpus(3, None, None);
+        assert_eq!(
+            config.get_vcpu_affinities_pcpu_ids(),
+            [(0, None, 0), (1, None, 1), (2, None, 2)]
+        );
+
+        let config = config_with_cpus(2, Some(vec![0x100, 0x200]), Some(vec![0x1, 0x6]));
+        assert_eq!(
+            config.get_vcpu_affinities_pcpu_ids(),
+            [(0, Some(0x1), 0x100), (1, Some(0x6), 0x200)]
+        );
+    }
+
+    #[test]
+    fn vcpu_phys_ids_duplicate() {
+        assert_eq!(
+            config_with_cpus(3, Some(vec![0x100, 0x101, 0x100]), None).validate(),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn vcpu_phys_ids_length_mismatch() {
+        assert_eq!(
+            config_with_cpus(2, Some(vec![0x100]), None).validate(),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            config_with_cpus(2, Some(vec![0x100, 0x101, 0x102]), None).validate(),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            config_with_cpus(2, None, Some(vec![0x1])).validate(),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    fn config_with_regions(
+        memory_regions: &[(usize, usize)],
+        devices: &[(usize, usize)],
+    ) -> AxVMConfig {
+        AxVMConfig {
+            memory_regions: memory_regions
+                .iter()
+                .map(|&(gpa, size)| VmMemConfig {
+                    gpa,
+                    size,
+                    ..Default::default()
+                })
+                .collect(),
+            emu_devices: devices
+                .iter()
+                .map(|&(base_gpa, length)| EmulatedDeviceConfig {
+                    base_gpa,
+                    length,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn regions_disjoint() {
+        let config = config_with_regions(
+            &[(0x8000_0000, 0x1000_0000), (0x9000_0000, 0x1000)],
+            &[(0x900_0000, 0x1000)],
+        );
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn regions_overlap() {
+        // Two memory regions.
+        let config = config_with_regions(&[(0x8000_0000, 0x2000), (0x8000_1000, 0x2000)], &[]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+        // A memory region and a device.
+        let config = config_with_regions(&[(0x8000_0000, 0x2000)], &[(0x8000_1000, 0x1000)]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+        // Two devices.
+        let config = config_with_regions(&[], &[(0x900_0000, 0x1000), (0x900_0fff, 0x1000)]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+    }
+
+    #[test]
+    fn empty_memory_region() {
+        let config = config_with_regions(&[(0x8000_0000, 0x1000), (0x9000_0000, 0)], &[]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+    }
+
+    #[test]
+    fn regions_out_of_address_space() {
+        let end = VM_ASPACE_BASE + VM_ASPACE_SIZE;
+        // Ending exactly at the top of the address space is fine.
+        let config = config_with_regions(&[(end - 0x1000, 0x1000)], &[]);
+        assert_eq!(config.validate(), Ok(()));
+        let config = config_with_regions(&[(end - 0x1000, 0x2000)], &[]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+        let config = config_with_regions(&[], &[(end, 0x1000)]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+        // The end address overflows.
+        let config = config_with_regions(&[(usize::MAX - 0xfff, 0x2000)], &[]);
+        assert_eq!(config.validate(), Err(AxError::InvalidInput));
+    }
+}
@@ -0,0 +1,124 @@
+//! Lightweight records of vCPU exits, kept for introspection while the VM runs.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axvcpu::AxVCpuExitReason;
+
+/// The kind of a vCPU exit.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitKind {
+    /// The vCPU has not exited yet.
+    None = 0,
+    /// A guest MMIO read.
+    MmioRead,
+    /// A guest MMIO write.
+    MmioWrite,
+    /// A guest port I/O read.
+    IoRead,
+    /// A guest port I/O write.
+    IoWrite,
+    /// A guest hypercall.
+    Hypercall,
+    /// A host external interrupt.
+    ExternalInterrupt,
+    /// A stage-2 (nested) page fault.
+    NestedPageFault,
+    /// The guest halted the vCPU.
+    Halt,
+    /// The guest requested a system shutdown.
+    SystemDown,
+    /// Any other exit.
+    Other,
+}
+
+impl VmExitKind {
+    /// The number of exit kinds.
+    pub const COUNT: usize = 11;
+
+    /// All exit kinds, indexed by their discriminant.
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::None,
+        Self::MmioRead,
+        Self::MmioWrite,
+        Self::IoRead,
+        Self::IoWrite,
+        Self::Hypercall,
+        Self::ExternalInterrupt,
+        Self::NestedPageFault,
+        Self::Halt,
+        Self::SystemDown,
+        Self::Other,
+    ];
+}
+
+/// A summary of a vCPU exit: its kind and its most relevant argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmExitInfo {
+    /// The kind of the exit.
+    pub kind: VmExitKind,
+    /// The faulting address, port, hypercall number or interrupt vector, 0 if not applicable.
+    pub arg: usize,
+}
+
+impl From<&AxVCpuExitReason> for VmExitInfo {
+    fn from(reason: &AxVCpuExitReason) -> Self {
+        let (kind, arg) = match reason {
+            AxVCpuExitReason::MmioRead { addr, .. } => (VmExitKind::MmioRead, addr.as_usize()),
+            AxVCpuExitReason::MmioWrite { addr, .. } => (VmExitKind::MmioWrite, addr.as_usize()),
+            AxVCpuExitReason::IoRead { port, .. } => (VmExitKind::IoRead, *port as usize),
+            AxVCpuExitReason::IoWrite { port, .. } => (VmExitKind::IoWrite, *port as usize),
+            AxVCpuExitReason::Hypercall { nr, .. } => (VmExitKind::Hypercall, *nr as usize),
+            AxVCpuExitReason::ExternalInterrupt { vector } => {
+                (VmExitKind::ExternalInterrupt, *vector as usize)
+            }
+            AxVCpuExitReason::NestedPageFault { addr, .. } => {
+                (VmExitKind::NestedPageFault, addr.as_usize())
+            }
+            AxVCpuExitReason::Halt => (VmExitKind::Halt, 0),
+            AxVCpuExitReason::SystemDown => (VmExitKind::SystemDown, 0),
+            _ => (VmExitKind::Other, 0),
+        };
+        Self { kind, arg }
+    }
+}
+
+impl fmt::Display for VmExitInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            VmExitKind::None | VmExitKind::Halt | VmExitKind::SystemDown | VmExitKind::Other => {
+                write!(f, "{:?}", self.kind)
+            }
+            _ => write!(f, "{:?}({:#x})", self.kind, self.arg),
+        }
+    }
+}
+
+/// The most recent exit of a vCPU, updated without locking on every exit.
+///
+/// The kind and the argument are stored separately,
+/// so a concurrent reader may observe them from two consecutive exits.
+#[derive(Default)]
+pub(crate) struct LastExit {
+    kind: AtomicUsize,
+    arg: AtomicUsize,
+}
+
+impl LastExit {
+    pub fn record(&self, info: VmExitInfo) {
+        self.arg.store(info.arg, Ordering::Relaxed);
+        self.kind.store(info.kind as usize, Ordering::Relaxed);
+    }
+
+    pub fn load(&self) -> VmExitInfo {
+        let kind = VmExitKind::ALL
+            .get(self.kind.load(Ordering::Relaxed))
+            .copied()
+            .unwrap_or(VmExitKind::Other);
+        VmExitInfo {
+            kind,
+            arg: self.arg.load(Ordering::Relaxed),
+        }
+    }
+}
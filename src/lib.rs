@@ -12,16 +12,28 @@ extern crate alloc;
 #[macro_use]
 extern crate log;
 
+mod exit;
 mod hal;
+mod mmio;
+mod stats;
 mod vcpu;
 mod vm;
 
 pub mod config;
 
+use axaddrspace::GuestPhysAddr;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
+
+pub use exit::{VmExitInfo, VmExitKind};
 pub use hal::AxVMHal;
+pub use mmio::{DoorbellCallback, MmioAccess, MmioHandler, DOORBELL_REG_SIZE};
+pub use stats::VmStatsSnapshot;
 pub use vm::AxVCpuRef;
 pub use vm::AxVM;
 pub use vm::AxVMRef;
+pub use vm::MemorySnapshot;
+#[cfg(feature = "debug")]
+pub use vm::PteInfo;
 
 /// The architecture-independent per-CPU type.
 pub type AxVMPerCpu<U> = axvcpu::AxPerCpu<vcpu::AxVMArchPerCpuImpl<U>>;
@@ -30,3 +42,64 @@ pub type AxVMPerCpu<U> = axvcpu::AxPerCpu<vcpu::AxVMArchPerCpuImpl<U>>;
 pub fn has_hardware_support() -> bool {
     vcpu::has_hardware_support()
 }
+
+/// The name of the architecture of the vCPUs, e.g. `"aarch64"`.
+pub const fn arch_name() -> &'static str {
+    vcpu::ARCH_NAME
+}
+
+/// Returns an iterator over the 4K-aligned guest page frames covering `[start, start + size)`.
+///
+/// A range reaching past the top of the address space is cut off at the last page.
+pub fn gpa_pages(start: GuestPhysAddr, size: usize) -> impl Iterator<Item = GuestPhysAddr> {
+    let first = start.align_down_4k().as_usize();
+    let count = match size {
+        0 => 0,
+        _ => {
+            let last = memory_addr::align_down_4k(start.as_usize().saturating_add(size - 1));
+            (last - first) / PAGE_SIZE_4K + 1
+        }
+    };
+    (0..count).map(move |i| GuestPhysAddr::from(first + i * PAGE_SIZE_4K))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn pages(start: usize, size: usize) -> Vec<usize> {
+        gpa_pages(GuestPhysAddr::from(start), size)
+            .map(|gpa| gpa.as_usize())
+            .collect()
+    }
+
+    #[test]
+    fn gpa_pages_empty_range() {
+        assert!(pages(0x1000, 0).is_empty());
+        assert!(pages(0x1234, 0).is_empty());
+    }
+
+    #[test]
+    fn gpa_pages_aligned_range() {
+        assert_eq!(pages(0x1000, 0x1000), [0x1000]);
+        assert_eq!(pages(0x1000, 0x2000), [0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn gpa_pages_unaligned_range() {
+        assert_eq!(pages(0x1fff, 1), [0x1000]);
+        assert_eq!(pages(0x1fff, 2), [0x1000, 0x2000]);
+        assert_eq!(pages(0x1800, 0x1000), [0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn gpa_pages_top_of_address_space() {
+        let last = usize::MAX & !(PAGE_SIZE_4K - 1);
+        assert_eq!(pages(last, PAGE_SIZE_4K), [last]);
+        assert_eq!(
+            pages(last - PAGE_SIZE_4K, usize::MAX),
+            [last - PAGE_SIZE_4K, last]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Lightweight MMIO and port I/O emulation through host closures, without a full device implementation.
+
+use alloc::boxed::Box;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use memory_addr::AddrRange;
+
+/// The size in bytes of a doorbell register.
+pub const DOORBELL_REG_SIZE: usize = 4;
+
+/// The host callback invoked with the written value when the guest rings a doorbell.
+pub type DoorbellCallback = Box<dyn Fn(u32) + Send>;
+
+/// A guest access to an MMIO or port I/O range served by an [`MmioHandler`].
+#[derive(Debug, Clone, Copy)]
+pub enum MmioAccess {
+    /// A read of `width` bytes at `offset` from the start of the range.
+    Read {
+        /// The offset of the access from the start of the range.
+        offset: usize,
+        /// The access width in bytes.
+        width: usize,
+    },
+    /// A write of `value` with `width` bytes at `offset` from the start of the range.
+    Write {
+        /// The offset of the access from the start of the range.
+        offset: usize,
+        /// The access width in bytes.
+        width: usize,
+        /// The value written by the guest.
+        value: usize,
+    },
+}
+
+/// A host closure serving guest accesses to an MMIO or port I/O range.
+///
+/// For reads, the returned value is passed to the guest, and `None` reads as zero.
+/// For writes, the returned value is ignored.
+pub type MmioHandler = Box<dyn FnMut(MmioAccess) -> Option<usize> + Send>;
+
+/// An MMIO range served by an [`MmioHandler`].
+pub(crate) struct InlineMmioRegion {
+    range: AddrRange<GuestPhysAddr>,
+    handler: MmioHandler,
+}
+
+impl InlineMmioRegion {
+    pub fn new(range: Range<GuestPhysAddr>, handler: MmioHandler) -> Self {
+        Self {
+            range: AddrRange::new(range.start, range.end),
+            handler,
+        }
+    }
+
+    /// Builds a doorbell: a single 32-bit register at `gpa` whose writes invoke `on_kick`.
+    pub fn doorbell(gpa: GuestPhysAddr, on_kick: DoorbellCallback) -> Self {
+        Self::new(
+            gpa..gpa + DOORBELL_REG_SIZE,
+            Box::new(move |access| {
+                if let MmioAccess::Write { value, .. } = access {
+                    on_kick(value as u32);
+                }
+                None
+            }),
+        )
+    }
+
+    pub fn range(&self) -> AddrRange<GuestPhysAddr> {
+        self.range
+    }
+
+    pub fn handle_read(&mut self, addr: GuestPhysAddr, width: usize) -> usize {
+        let offset = addr.as_usize() - self.range.start.as_usize();
+        (self.handler)(MmioAccess::Read { offset, width }).unwrap_or(0)
+    }
+
+    pub fn handle_write(&mut self, addr: GuestPhysAddr, width: usize, value: usize) {
+        let offset = addr.as_usize() - self.range.start.as_usize();
+        (self.handler)(MmioAccess::Write {
+            offset,
+            width,
+            value,
+        });
+    }
+}
+
+/// A port I/O range served by an [`MmioHandler`].
+pub(crate) struct InlinePortRegion {
+    ports: Range<u16>,
+    handler: MmioHandler,
+}
+
+impl InlinePortRegion {
+    pub fn new(ports: Range<u16>, handler: MmioHandler) -> Self {
+        Self { ports, handler }
+    }
+
+    pub fn ports(&self) -> &Range<u16> {
+        &self.ports
+    }
+
+    pub fn handle_read(&mut self, port: u16, width: usize) -> usize {
+        let offset = (port - self.ports.start) as usize;
+        (self.handler)(MmioAccess::Read { offset, width }).unwrap_or(0)
+    }
+
+    pub fn handle_write(&mut self, port: u16, width: usize, value: usize) {
+        let offset = (port - self.ports.start) as usize;
+        (self.handler)(MmioAccess::Write {
+            offset,
+            width,
+            value,
+        });
+    }
+}
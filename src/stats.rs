@@ -0,0 +1,45 @@
+//! Per-VM statistics, updated lock-free by vCPUs while they run.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::exit::VmExitKind;
+
+/// Live statistics counters of a VM.
+#[derive(Default)]
+pub(crate) struct VmStats {
+    exits: [AtomicU64; VmExitKind::COUNT],
+}
+
+impl VmStats {
+    pub fn record_exit(&self, kind: VmExitKind) {
+        self.exits[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn exit_counts(&self) -> [u64; VmExitKind::COUNT] {
+        core::array::from_fn(|i| self.exits[i].load(Ordering::Relaxed))
+    }
+}
+
+/// A point-in-time copy of a VM's statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct VmStatsSnapshot {
+    /// The number of vCPUs inside [`AxVM::run_vcpu`](crate::AxVM::run_vcpu) at the time of the snapshot,
+    /// see [`AxVM::vcpus_in_guest`](crate::AxVM::vcpus_in_guest).
+    pub vcpus_in_guest: usize,
+    /// The number of VM exits of each kind, indexed by [`VmExitKind`] discriminant.
+    pub exits: [u64; VmExitKind::COUNT],
+    /// The cumulative time in nanoseconds all vCPUs have spent running the guest.
+    pub guest_time_nanos: u64,
+}
+
+impl VmStatsSnapshot {
+    /// Returns the number of VM exits of the given kind.
+    pub fn exit_count(&self, kind: VmExitKind) -> u64 {
+        self.exits[kind as usize]
+    }
+
+    /// Returns the total number of VM exits.
+    pub fn total_exits(&self) -> u64 {
+        self.exits.iter().sum()
+    }
+}
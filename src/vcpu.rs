@@ -6,6 +6,7 @@ cfg_if::cfg_if! {
         pub use x86_vcpu::VmxArchPerCpuState as AxVMArchPerCpuImpl;
         pub use x86_vcpu::has_hardware_support;
         pub type AxVCpuCreateConfig = ();
+        pub const ARCH_NAME: &str = "x86_64";
 
         // Note:
         // According to the requirements of `x86_vcpu`,
@@ -18,10 +19,12 @@ cfg_if::cfg_if! {
         pub use riscv_vcpu::RISCVPerCpu as AxVMArchPerCpuImpl;
         pub use riscv_vcpu::RISCVVCpuCreateConfig as AxVCpuCreateConfig;
         pub use riscv_vcpu::has_hardware_support;
+        pub const ARCH_NAME: &str = "riscv64";
     } else if #[cfg(target_arch = "aarch64")] {
         pub use arm_vcpu::Aarch64VCpu as AxArchVCpuImpl;
         pub use arm_vcpu::Aarch64PerCpu as AxVMArchPerCpuImpl;
         pub use arm_vcpu::Aarch64VCpuCreateConfig as AxVCpuCreateConfig;
         pub use arm_vcpu::has_hardware_support;
+        pub const ARCH_NAME: &str = "aarch64";
     }
 }
@@ -1,9 +1,12 @@
 use alloc::boxed::Box;
 use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 // use core::cell::UnsafeCell;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::mem::{align_of, size_of};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 
 use axdevice::{AxVmDeviceConfig, AxVmDevices};
 use axerrno::{ax_err, ax_err_type, AxResult};
@@ -12,13 +15,20 @@ use spin::Mutex;
 use axvcpu::{AxArchVCpu, AxVCpu, AxVCpuExitReason, AxVCpuHal};
 
 use axaddrspace::{AddrSpace, GuestPhysAddr, HostPhysAddr, MappingFlags};
+use memory_addr::{AddrRange, MemoryAddr, PAGE_SIZE_4K};
+use page_table_multiarch::PagingHandler;
 
-use crate::config::AxVMConfig;
+use crate::config::{AxVMConfig, VmMemConfig};
+use crate::exit::{LastExit, VmExitInfo};
+use crate::mmio::{
+    DoorbellCallback, InlineMmioRegion, InlinePortRegion, MmioHandler, DOORBELL_REG_SIZE,
+};
+use crate::stats::{VmStats, VmStatsSnapshot};
 use crate::vcpu::{AxArchVCpuImpl, AxVCpuCreateConfig};
-use crate::{has_hardware_support, AxVMHal};
+use crate::{gpa_pages, has_hardware_support, AxVMHal};
 
-const VM_ASPACE_BASE: usize = 0x0;
-const VM_ASPACE_SIZE: usize = 0x7fff_ffff_f000;
+pub(crate) const VM_ASPACE_BASE: usize = 0x0;
+pub(crate) const VM_ASPACE_SIZE: usize = 0x7fff_ffff_f000;
 
 /// A vCPU with architecture-independent interface.
 #[allow(type_alias_bounds)]
@@ -34,6 +44,10 @@ struct AxVMInnerConst<U: AxVCpuHal> {
     id: usize,
     config: AxVMConfig,
     vcpu_list: Box<[AxVCpuRef<U>]>,
+    /// Nanoseconds each vCPU has spent running the guest, indexed by vCPU id.
+    vcpu_guest_time_nanos: Box<[AtomicU64]>,
+    /// The most recent exit of each vCPU, indexed by vCPU id.
+    vcpu_last_exits: Box<[LastExit]>,
     devices: AxVmDevices,
 }
 
@@ -43,12 +57,43 @@ unsafe impl<U: AxVCpuHal> Sync for AxVMInnerConst<U> {}
 struct AxVMInnerMut<H: AxVMHal> {
     // Todo: use more efficient lock.
     address_space: Mutex<AddrSpace<H::PagingHandler>>,
+    /// The memory regions currently mapped in `address_space`.
+    /// Must be locked after `address_space` and before `inline_mmio` when several are needed.
+    memory_regions: Mutex<Vec<VmMemConfig>>,
+    inline_mmio: Mutex<Vec<InlineMmioRegion>>,
+    port_io: Mutex<Vec<InlinePortRegion>>,
     _marker: core::marker::PhantomData<H>,
 }
 
+/// A single mapping in the VM's two-stage address translation page table.
+#[cfg(feature = "debug")]
+#[derive(Debug, Clone, Copy)]
+pub struct PteInfo {
+    /// The guest physical address where the mapping starts.
+    pub gpa: GuestPhysAddr,
+    /// The host physical address the mapping translates to.
+    pub hpa: HostPhysAddr,
+    /// The size of the mapped page.
+    pub size: usize,
+    /// The mapping flags of the page.
+    pub flags: MappingFlags,
+}
+
+/// A copy of the contents of a guest RAM region.
+#[derive(Debug, Clone)]
+pub struct MemorySnapshot {
+    /// The start address of the region in GPA.
+    pub gpa: GuestPhysAddr,
+    /// The contents of the region.
+    pub data: Vec<u8>,
+}
+
 /// A Virtual Machine.
 pub struct AxVM<H: AxVMHal, U: AxVCpuHal> {
     running: AtomicBool,
+    vcpus_in_guest: AtomicUsize,
+    time_offset_nanos: AtomicI64,
+    stats: VmStats,
     inner_const: AxVMInnerConst<U>,
     inner_mut: AxVMInnerMut<H>,
 }
@@ -58,6 +103,8 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
     /// Returns an error if the configuration is invalid.
     /// The VM is not started until `boot` is called.
     pub fn new(config: AxVMConfig) -> AxResult<AxVMRef<H, U>> {
+        config.validate()?;
+
         let result = Arc::new({
             let vcpu_id_pcpu_sets = config.get_vcpu_affinities_pcpu_ids();
 
@@ -91,36 +138,18 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
             // Set up Memory regions.
             let mut address_space =
                 AddrSpace::new_empty(GuestPhysAddr::from(VM_ASPACE_BASE), VM_ASPACE_SIZE)?;
+            let mut memory_regions = Vec::with_capacity(config.memory_regions().len());
             for mem_region in config.memory_regions() {
-                let mapping_flags = MappingFlags::from_bits(mem_region.flags).ok_or_else(|| {
-                    ax_err_type!(
-                        InvalidInput,
-                        format!("Illegal flags {:?}", mem_region.flags)
-                    )
-                })?;
-
-                // Handle passthrough device's memory region.
-                // Todo: Perhaps we can merge the management of passthrough device memory
-                //       into the device configuration file.
-                if mapping_flags.contains(MappingFlags::DEVICE) {
-                    address_space.map_linear(
-                        GuestPhysAddr::from(mem_region.gpa),
-                        HostPhysAddr::from(mem_region.gpa),
-                        mem_region.size,
-                        mapping_flags,
-                    )?;
+                let region = if config.trap_null_page() {
+                    match skip_null_page(mem_region.clone()) {
+                        Some(region) => region,
+                        None => continue,
+                    }
                 } else {
-                    // Handle ram region.
-                    // Note: currently we use `map_alloc`,
-                    // which allocates real physical memory in units of physical page frames,
-                    // which may not be contiguous!!!
-                    address_space.map_alloc(
-                        GuestPhysAddr::from(mem_region.gpa),
-                        mem_region.size,
-                        mapping_flags,
-                        true,
-                    )?;
-                }
+                    mem_region.clone()
+                };
+                map_memory_region(&mut address_space, &region)?;
+                memory_regions.push(region);
             }
 
             let devices = axdevice::AxVmDevices::new(AxVmDeviceConfig {
@@ -129,20 +158,33 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
 
             Self {
                 running: AtomicBool::new(false),
+                vcpus_in_guest: AtomicUsize::new(0),
+                time_offset_nanos: AtomicI64::new(0),
+                stats: VmStats::default(),
                 inner_const: AxVMInnerConst {
                     id: config.id(),
                     config,
+                    vcpu_guest_time_nanos: vcpu_list.iter().map(|_| AtomicU64::new(0)).collect(),
+                    vcpu_last_exits: vcpu_list.iter().map(|_| LastExit::default()).collect(),
                     vcpu_list: vcpu_list.into_boxed_slice(),
                     devices,
                 },
                 inner_mut: AxVMInnerMut {
                     address_space: Mutex::new(address_space),
+                    memory_regions: Mutex::new(memory_regions),
+                    inline_mmio: Mutex::new(Vec::new()),
+                    port_io: Mutex::new(Vec::new()),
                     _marker: core::marker::PhantomData,
                 },
             }
         });
 
-        info!("VM created: id={}", result.id());
+        info!(
+            "[vm={}] VM created with {} {} vCPU(s)",
+            result.id(),
+            result.vcpu_num(),
+            crate::arch_name()
+        );
 
         // Setup VCpus.
         for vcpu in result.vcpu_list() {
@@ -157,7 +199,7 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
                 <AxArchVCpuImpl<U> as AxArchVCpu>::SetupConfig::default(),
             )?;
         }
-        info!("VM setup: id={}", result.id());
+        info!("[vm={}] VM setup", result.id());
 
         Ok(result)
     }
@@ -187,16 +229,250 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
         &self.inner_const.vcpu_list
     }
 
+    /// Returns the number of vCPUs of the VM which are currently inside [`AxVM::run_vcpu`].
+    ///
+    /// This is a transient count, not whether vCPUs are online from the guest's point of view:
+    /// it drops every time a vCPU returns an exit to its caller, e.g. on a halt or hypercall.
+    /// It reaches zero once the caller has stopped re-entering all vCPUs.
+    pub fn vcpus_in_guest(&self) -> usize {
+        self.vcpus_in_guest.load(Ordering::Relaxed)
+    }
+
+    /// Returns the ids of the vCPUs corresponding to the VM.
+    pub fn vcpu_ids(&self) -> Vec<usize> {
+        self.vcpu_list().iter().map(|vcpu| vcpu.id()).collect()
+    }
+
+    /// Returns the physical (hardware) ids of the vCPUs corresponding to the VM, indexed by vCPU id.
+    pub fn vcpu_hard_ids(&self) -> Vec<usize> {
+        self.inner_const
+            .config
+            .get_vcpu_affinities_pcpu_ids()
+            .into_iter()
+            .map(|(_vcpu_id, _phys_cpu_set, phys_id)| phys_id)
+            .collect()
+    }
+
+    /// Returns the total time in nanoseconds that all vCPUs of the VM have spent running the guest.
+    pub fn total_guest_time_nanos(&self) -> u64 {
+        self.inner_const
+            .vcpu_guest_time_nanos
+            .iter()
+            .map(|t| t.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the time in nanoseconds that each vCPU has spent running the guest, indexed by vCPU id.
+    pub fn vcpu_guest_time_nanos(&self) -> Vec<u64> {
+        self.inner_const
+            .vcpu_guest_time_nanos
+            .iter()
+            .map(|t| t.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Returns the most recent exit of each vCPU, indexed by vCPU id.
+    ///
+    /// This is cheap to query while the VM runs, e.g. to see what each vCPU of a hung guest is doing.
+    pub fn last_exit_reason_per_vcpu(&self) -> Vec<VmExitInfo> {
+        self.inner_const
+            .vcpu_last_exits
+            .iter()
+            .map(LastExit::load)
+            .collect()
+    }
+
+    /// Returns a point-in-time copy of the VM's statistics.
+    pub fn stats(&self) -> VmStatsSnapshot {
+        VmStatsSnapshot {
+            vcpus_in_guest: self.vcpus_in_guest(),
+            exits: self.stats.exit_counts(),
+            guest_time_nanos: self.total_guest_time_nanos(),
+        }
+    }
+
     /// Returns the base address of the two-stage address translation page table for the VM.
+    ///
+    /// This is the host physical address of the root of the stage-2 (or EPT) page table,
+    /// which can be shared with e.g. an IOMMU or a nested hypervisor to use the same translation.
     pub fn ept_root(&self) -> HostPhysAddr {
         self.inner_mut.address_space.lock().page_table_root()
     }
 
+    /// Walks the two-stage address translation page table over the given GPA range,
+    /// and returns every mapping found, in ascending GPA order.
+    ///
+    /// Only the parts of the range covered by the VM's memory regions are walked,
+    /// the gaps between them hold no mappings and are skipped.
+    #[cfg(feature = "debug")]
+    pub fn dump_page_table(&self, range: Range<GuestPhysAddr>) -> Vec<PteInfo> {
+        let addr_space = self.inner_mut.address_space.lock();
+        let mut spans = self
+            .inner_mut
+            .memory_regions
+            .lock()
+            .iter()
+            .filter_map(|r| {
+                let region = AddrRange::from_start_size(GuestPhysAddr::from(r.gpa), r.size);
+                let start = region.start.max(range.start);
+                let end = region.end.min(range.end);
+                (start < end).then_some(start..end)
+            })
+            .collect::<Vec<_>>();
+        spans.sort_unstable_by_key(|span| span.start);
+
+        let mut entries = Vec::new();
+        let mut gpa = GuestPhysAddr::from(0);
+        for span in spans {
+            // A huge page may already have covered the start of this span.
+            gpa = gpa.max(span.start.align_down_4k());
+            while gpa < span.end {
+                match addr_space.page_table().query(gpa.as_usize().into()) {
+                    Ok((hpa, flags, page_size)) => {
+                        let size: usize = page_size.into();
+                        let entry_gpa = gpa.align_down(size);
+                        entries.push(PteInfo {
+                            gpa: entry_gpa,
+                            hpa: HostPhysAddr::from(hpa.as_usize()).align_down(size),
+                            size,
+                            flags,
+                        });
+                        gpa = entry_gpa + size;
+                    }
+                    Err(_) => gpa += PAGE_SIZE_4K,
+                }
+            }
+        }
+        entries
+    }
+
+    /// Checks the internal consistency of the VM, panicking on the first violated invariant.
+    ///
+    /// - memory regions are non-empty, and eagerly populated ones are mapped in the address space;
+    /// - inline MMIO regions overlap neither each other, emulated devices nor memory regions;
+    /// - emulated devices do not overlap memory regions, including hot-added ones.
+    #[cfg(feature = "debug")]
+    pub fn assert_invariants(&self) {
+        let addr_space = self.inner_mut.address_space.lock();
+        let memory_regions = self.inner_mut.memory_regions.lock();
+        let inline_mmio = self.inner_mut.inline_mmio.lock();
+        assert_memory_regions_mapped(&addr_space, &memory_regions);
+        let devices = self
+            .inner_const
+            .config
+            .emu_devices()
+            .iter()
+            .map(|dev| AddrRange::from_start_size(GuestPhysAddr::from(dev.base_gpa), dev.length))
+            .collect::<Vec<_>>();
+        let inline_ranges = inline_mmio.iter().map(|r| r.range()).collect::<Vec<_>>();
+        assert_mmio_regions_disjoint(&memory_regions, &devices, &inline_ranges);
+    }
+
+    /// Returns the memory regions currently mapped into the VM, including hot-added ones.
+    pub fn memory_regions(&self) -> Vec<VmMemConfig> {
+        self.inner_mut.memory_regions.lock().clone()
+    }
+
+    /// Maps a new memory region into the VM, which may already be running,
+    /// and returns its base address.
+    ///
+    /// The region must not overlap existing memory regions or MMIO regions.
+    /// If the VM traps guest null pointer accesses, the first page of a region at GPA 0
+    /// is left unmapped, like for the regions mapped at VM creation.
+    /// The guest is not notified, it is up to the caller to tell the guest about the new memory,
+    /// e.g. through a device tree update or a hotplug event.
+    pub fn hotplug_memory(&self, mut region: VmMemConfig) -> AxResult<GuestPhysAddr> {
+        if self.inner_const.config.trap_null_page() && region.gpa == 0 {
+            region = skip_null_page(region.clone()).ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!(
+                        "Memory region {:#x}+{:#x} only covers the trapped null page",
+                        region.gpa, region.size
+                    )
+                )
+            })?;
+        }
+        let gpa = GuestPhysAddr::from(region.gpa);
+        let range = AddrRange::try_from_start_size(gpa, region.size)
+            .filter(|r| !r.is_empty())
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("Invalid memory region {:#x}+{:#x}", region.gpa, region.size)
+                )
+            })?;
+        let mut addr_space = self.inner_mut.address_space.lock();
+        let mut memory_regions = self.inner_mut.memory_regions.lock();
+        // Keep the inline MMIO list locked until the region is added,
+        // so that no inline MMIO handler can be registered over it meanwhile.
+        let inline_mmio = self.inner_mut.inline_mmio.lock();
+        if self.inner_const.config.emu_devices().iter().any(|dev| {
+            AddrRange::from_start_size(GuestPhysAddr::from(dev.base_gpa), dev.length)
+                .overlaps(range)
+        }) || inline_mmio.iter().any(|r| r.range().overlaps(range))
+        {
+            return ax_err!(
+                AlreadyExists,
+                format!("Memory region {:?} overlaps an MMIO region", range)
+            );
+        }
+        if memory_regions
+            .iter()
+            .any(|r| AddrRange::from_start_size(GuestPhysAddr::from(r.gpa), r.size).overlaps(range))
+        {
+            return ax_err!(
+                AlreadyExists,
+                format!(
+                    "Memory region {:?} overlaps an existing memory region",
+                    range
+                )
+            );
+        }
+        map_memory_region(&mut addr_space, &region)?;
+        memory_regions.push(region);
+        info!("[vm={}] Memory region {:?} hot-added", self.id(), range);
+        Ok(gpa)
+    }
+
+    /// Unmaps a sub-range of a guest RAM region and frees its backing frames,
+    /// e.g. to reclaim memory the guest has given back through a balloon driver.
+    ///
+    /// The range must be 4K-aligned and fully contained in a single non-device memory region,
+    /// which is split around the resulting hole.
+    ///
+    /// axvm does not invalidate stage-2 (or EPT) TLB entries, so a vCPU could keep accessing
+    /// the freed frames through a stale translation. Returns an error if any vCPU is inside
+    /// [`AxVM::run_vcpu`]; the caller must keep all vCPUs out of the guest during the call,
+    /// and invalidate the stage-2 TLB on every physical CPU which ran them before re-entering.
+    pub fn unmap_memory_range(&self, gpa: GuestPhysAddr, size: usize) -> AxResult {
+        let mut addr_space = self.inner_mut.address_space.lock();
+        if self.vcpus_in_guest() != 0 {
+            return ax_err!(
+                BadState,
+                format!(
+                    "[vm={}] Can not unmap memory while {} vCPUs are in the guest",
+                    self.id(),
+                    self.vcpus_in_guest()
+                )
+            );
+        }
+        unmap_ram_range(
+            &mut addr_space,
+            &mut self.inner_mut.memory_regions.lock(),
+            gpa,
+            size,
+        )
+    }
+
     /// Returns guest VM image load region in `Vec<&'static mut [u8]>`,
     /// according to the given `image_load_gpa` and `image_size.
     /// `Vec<&'static mut [u8]>` is a series of (HVA) address segments,
     /// which may correspond to non-contiguous physical addresses,
     ///
+    /// Returns an error if the image is empty, exceeds the configured maximum image size,
+    /// or is not fully backed by guest memory.
+    ///
     /// FIXME:
     /// Find a more elegant way to manage potentially non-contiguous physical memory
     ///         instead of `Vec<&'static mut [u8]>`.
@@ -205,11 +481,168 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
         image_load_gpa: GuestPhysAddr,
         image_size: usize,
     ) -> AxResult<Vec<&'static mut [u8]>> {
-        let addr_space = self.inner_mut.address_space.lock();
-        let image_load_hva = addr_space
-            .translated_byte_buffer(image_load_gpa, image_size)
-            .expect("Failed to translate kernel image load address");
-        Ok(image_load_hva)
+        image_load_region(
+            &mut self.inner_mut.address_space.lock(),
+            image_load_gpa,
+            image_size,
+            self.inner_const.config.image_config().max_image_size,
+        )
+    }
+
+    /// Copies guest memory starting at `gpa` into `buf`.
+    ///
+    /// Returns an error if any part of the range is not mapped, in which case `buf` is left untouched.
+    pub fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        read_guest_bytes(&mut self.inner_mut.address_space.lock(), gpa, buf)
+    }
+
+    /// Copies `data` into guest memory starting at `gpa`.
+    ///
+    /// Returns an error if any part of the range is not mapped, in which case nothing is written.
+    pub fn write_guest(&self, gpa: GuestPhysAddr, data: &[u8]) -> AxResult {
+        write_guest_bytes(&mut self.inner_mut.address_space.lock(), gpa, data)
+    }
+
+    /// Reads a value of type `T` from guest memory at `gpa`.
+    ///
+    /// Returns an error if `gpa` is not aligned for `T`, or the value's range is not fully mapped.
+    ///
+    /// # Safety
+    ///
+    /// Every bit pattern must be a valid value of `T`, as the value is taken from guest memory.
+    pub unsafe fn read_guest_of<T: Copy>(&self, gpa: GuestPhysAddr) -> AxResult<T> {
+        check_guest_alignment::<T>(gpa)?;
+        let mut buf = alloc::vec![0u8; size_of::<T>()];
+        self.read_guest(gpa, &mut buf)?;
+        // SAFETY: `buf` holds `size_of::<T>()` initialized bytes, which form a valid `T`
+        // by the caller's guarantee.
+        Ok(unsafe { buf.as_ptr().cast::<T>().read_unaligned() })
+    }
+
+    /// Writes `value` into guest memory at `gpa`.
+    ///
+    /// Returns an error if `gpa` is not aligned for `T`, or the value's range is not fully mapped.
+    ///
+    /// # Safety
+    ///
+    /// `T` must not contain padding bytes, as all bytes of `value` are copied to the guest.
+    pub unsafe fn write_guest_of<T: Copy>(&self, gpa: GuestPhysAddr, value: &T) -> AxResult {
+        check_guest_alignment::<T>(gpa)?;
+        // SAFETY: `value` is a valid reference, whose bytes are all initialized
+        // by the caller's guarantee.
+        let bytes = unsafe {
+            core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>())
+        };
+        self.write_guest(gpa, bytes)
+    }
+
+    /// Reads a NUL-terminated string from guest memory at `gpa`.
+    ///
+    /// At most `max` bytes are read, or a single page if `max` is `None`,
+    /// and invalid UTF-8 sequences are replaced. Returns an error if the guest memory
+    /// holding the string is not mapped.
+    pub fn read_guest_cstr(&self, gpa: GuestPhysAddr, max: Option<usize>) -> AxResult<String> {
+        let max = max.unwrap_or(PAGE_SIZE_4K);
+        let mut addr_space = self.inner_mut.address_space.lock();
+        let mut bytes = Vec::new();
+        // Translate one page at a time, the string may end before the next page is mapped.
+        for page in gpa_pages(gpa, max) {
+            let cur = page.max(gpa);
+            let chunk_len = (PAGE_SIZE_4K - cur.align_offset_4k()).min(max - bytes.len());
+            populate_guest_range(&mut addr_space, cur, chunk_len);
+            let chunks = addr_space
+                .translated_byte_buffer(cur, chunk_len)
+                .ok_or_else(|| {
+                    ax_err_type!(
+                        InvalidInput,
+                        format!("Guest string at {:?} is not mapped at {:?}", gpa, cur)
+                    )
+                })?;
+            for chunk in chunks {
+                if let Some(nul) = chunk.iter().position(|&b| b == 0) {
+                    bytes.extend_from_slice(&chunk[..nul]);
+                    return Ok(String::from_utf8_lossy(&bytes).into_owned());
+                }
+                bytes.extend_from_slice(chunk);
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Captures the contents of every guest RAM region, in mapping order.
+    ///
+    /// Passthrough device regions are skipped, and lazy regions are fully populated.
+    /// The VM should not be running, otherwise the snapshot may be inconsistent.
+    pub fn snapshot_memory(&self) -> AxResult<Vec<MemorySnapshot>> {
+        let mut addr_space = self.inner_mut.address_space.lock();
+        self.ram_regions()
+            .into_iter()
+            .map(|(gpa, size)| {
+                let mut data = alloc::vec![0; size];
+                read_guest_bytes(&mut addr_space, gpa, &mut data)?;
+                Ok(MemorySnapshot { gpa, data })
+            })
+            .collect()
+    }
+
+    /// Restores the contents of guest RAM from snapshots taken by [`AxVM::snapshot_memory`].
+    ///
+    /// Returns an error without touching guest memory if the snapshots do not match
+    /// the VM's RAM region layout, or if any part of it can not be translated.
+    pub fn restore_memory(&self, snapshots: &[MemorySnapshot]) -> AxResult {
+        // Memory regions only change with the address space locked, so the layout checked here
+        // stays valid until the copy is done.
+        let mut addr_space = self.inner_mut.address_space.lock();
+        let ram_regions = self.ram_regions();
+        let layout_matches = snapshots.len() == ram_regions.len()
+            && ram_regions
+                .iter()
+                .zip(snapshots)
+                .all(|(&(gpa, size), snapshot)| snapshot.gpa == gpa && snapshot.data.len() == size);
+        if !layout_matches {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "[vm={}] Memory snapshot does not match the memory region layout",
+                    self.id()
+                )
+            );
+        }
+        // Translate every region before copying, so that a failure leaves guest memory untouched.
+        let regions_hva = snapshots
+            .iter()
+            .map(|snapshot| {
+                translate_guest_range(&mut addr_space, snapshot.gpa, snapshot.data.len())
+            })
+            .collect::<AxResult<Vec<_>>>()?;
+        for (snapshot, chunks) in snapshots.iter().zip(regions_hva) {
+            copy_to_chunks(chunks, &snapshot.data);
+        }
+        Ok(())
+    }
+
+    /// Returns the mapping flags of the memory region containing `gpa`, if any.
+    fn region_flags(&self, gpa: GuestPhysAddr) -> Option<MappingFlags> {
+        self.inner_mut
+            .memory_regions
+            .lock()
+            .iter()
+            .find(|r| AddrRange::from_start_size(GuestPhysAddr::from(r.gpa), r.size).contains(gpa))
+            .and_then(|r| MappingFlags::from_bits(r.flags))
+    }
+
+    /// Returns the start address and size of each guest RAM region, in mapping order.
+    fn ram_regions(&self) -> Vec<(GuestPhysAddr, usize)> {
+        self.inner_mut
+            .memory_regions
+            .lock()
+            .iter()
+            .filter(|r| {
+                MappingFlags::from_bits(r.flags)
+                    .is_some_and(|flags| !flags.contains(MappingFlags::DEVICE))
+            })
+            .map(|r| (GuestPhysAddr::from(r.gpa), r.size))
+            .collect()
     }
 
     /// Returns if the VM is running.
@@ -222,19 +655,155 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
         if !has_hardware_support() {
             ax_err!(Unsupported, "Hardware does not support virtualization")
         } else if self.running() {
-            ax_err!(BadState, format!("VM[{}] is running", self.id()))
+            ax_err!(
+                BadState,
+                format!("[vm={}] VM is already running", self.id())
+            )
         } else {
-            info!("Booting VM[{}]", self.id());
+            info!("[vm={}] Booting VM", self.id());
             self.running.store(true, Ordering::Relaxed);
             Ok(())
         }
     }
 
+    /// Sets the offset in nanoseconds of the guest's perceived time from the host time.
+    ///
+    /// axvm does not emulate guest timers or RTCs, and the vCPUs read the hardware counter
+    /// directly, so nothing in this crate applies the offset: it is only stored here for the
+    /// embedder's timer and RTC emulation, which must use [`AxVM::guest_time_nanos`].
+    ///
+    /// The offset is shared by all vCPUs. Changing it while the VM is running makes
+    /// the guest observe a time jump in the emulation paths which apply it.
+    pub fn set_time_offset(&self, delta_ns: i64) {
+        self.time_offset_nanos.store(delta_ns, Ordering::Relaxed);
+    }
+
+    /// Returns the offset in nanoseconds of the guest's perceived time from the host time.
+    pub fn time_offset(&self) -> i64 {
+        self.time_offset_nanos.load(Ordering::Relaxed)
+    }
+
+    /// Current guest time in nanoseconds, i.e. the host time adjusted by the offset
+    /// set with [`AxVM::set_time_offset`].
+    ///
+    /// Timer and RTC emulation provided by the embedder should use this
+    /// instead of [`AxVMHal::current_time_nanos`].
+    pub fn guest_time_nanos(&self) -> u64 {
+        H::current_time_nanos().saturating_add_signed(self.time_offset())
+    }
+
     /// Returns this VM's emulated devices.
     pub fn get_devices(&self) -> &AxVmDevices {
         &self.inner_const.devices
     }
 
+    /// Looks up the emulated device whose MMIO region contains `gpa`.
+    ///
+    /// Returns the index of the device in the VM's emulated device configuration,
+    /// together with the base address and size of its MMIO region.
+    pub fn mmio_region_for(&self, gpa: GuestPhysAddr) -> Option<(usize, GuestPhysAddr, usize)> {
+        self.inner_const
+            .config
+            .emu_devices()
+            .iter()
+            .enumerate()
+            .map(|(idx, dev)| (idx, GuestPhysAddr::from(dev.base_gpa), dev.length))
+            .find(|&(_, base, size)| AddrRange::from_start_size(base, size).contains(gpa))
+    }
+
+    /// Registers a doorbell register at `gpa`.
+    ///
+    /// Each guest write to the 32-bit register at `gpa` invokes `on_kick` with the written value,
+    /// and reads from it return zero. See [`AxVM::set_mmio_handler`] for the calling context of `on_kick`.
+    pub fn add_doorbell(&self, gpa: GuestPhysAddr, on_kick: DoorbellCallback) -> AxResult {
+        if !gpa.is_aligned(DOORBELL_REG_SIZE) {
+            return ax_err!(
+                InvalidInput,
+                format!("Unaligned doorbell address {:?}", gpa)
+            );
+        }
+        self.add_inline_mmio(InlineMmioRegion::doorbell(gpa, on_kick))
+    }
+
+    /// Registers a host closure serving all guest MMIO accesses to `range`.
+    ///
+    /// `handler` is called on the vCPU's exit path with the VM's inline MMIO handler list locked,
+    /// so it must not register further MMIO handlers or doorbells.
+    pub fn set_mmio_handler(&self, range: Range<GuestPhysAddr>, handler: MmioHandler) -> AxResult {
+        if range.is_empty() {
+            return ax_err!(InvalidInput, format!("Empty MMIO range {:?}", range));
+        }
+        self.add_inline_mmio(InlineMmioRegion::new(range, handler))
+    }
+
+    /// Registers a host closure serving all guest port I/O accesses to `ports`.
+    ///
+    /// Reads from ports without a handler return all ones, and writes to them are dropped.
+    ///
+    /// Only 32-bit reads are served here: 8- and 16-bit reads are returned by [`AxVM::run_vcpu`]
+    /// as `IoRead` exits for the caller to complete, without invoking `handler`, as axvm can not
+    /// yet merge a narrow value into the guest's RAX.
+    /// `handler` is called on the vCPU's exit path with the VM's port handler list locked,
+    /// so it must not register further port handlers.
+    pub fn set_port_handler(&self, ports: Range<u16>, handler: MmioHandler) -> AxResult {
+        if ports.is_empty() {
+            return ax_err!(InvalidInput, format!("Empty port range {:?}", ports));
+        }
+        let mut port_io = self.inner_mut.port_io.lock();
+        if port_io
+            .iter()
+            .any(|r| r.ports().start < ports.end && ports.start < r.ports().end)
+        {
+            return ax_err!(
+                AlreadyExists,
+                format!("Port range {:?} overlaps an existing port handler", ports)
+            );
+        }
+        port_io.push(InlinePortRegion::new(ports, handler));
+        Ok(())
+    }
+
+    fn add_inline_mmio(&self, region: InlineMmioRegion) -> AxResult {
+        let range = region.range();
+        let overlaps_device = self.inner_const.config.emu_devices().iter().any(|dev| {
+            AddrRange::from_start_size(GuestPhysAddr::from(dev.base_gpa), dev.length)
+                .overlaps(range)
+        });
+        // Keep the memory regions locked until the handler is added,
+        // so that no memory can be hot-added over it meanwhile.
+        let memory_regions = self.inner_mut.memory_regions.lock();
+        if memory_regions
+            .iter()
+            .any(|r| AddrRange::from_start_size(GuestPhysAddr::from(r.gpa), r.size).overlaps(range))
+        {
+            return ax_err!(
+                AlreadyExists,
+                format!("MMIO range {:?} overlaps a memory region", range)
+            );
+        }
+        let mut inline_mmio = self.inner_mut.inline_mmio.lock();
+        if overlaps_device || inline_mmio.iter().any(|r| r.range().overlaps(range)) {
+            return ax_err!(
+                AlreadyExists,
+                format!("MMIO range {:?} overlaps an existing MMIO region", range)
+            );
+        }
+        if let Some(max) = self.inner_const.config.max_mmio_regions() {
+            if self.inner_const.config.emu_devices().len() + inline_mmio.len() >= max {
+                return ax_err!(
+                    NoMemory,
+                    format!(
+                        "[vm={}] Reached the limit of {} MMIO regions",
+                        self.id(),
+                        max
+                    )
+                );
+            }
+        }
+        inline_mmio.push(region);
+        Ok(())
+    }
+
     /// Run a vCPU according to the given vcpu_id.
     ///
     /// ## Arguments
@@ -249,10 +818,31 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
             .ok_or_else(|| ax_err_type!(InvalidInput, "Invalid vcpu_id"))?;
 
         vcpu.bind()?;
+        self.vcpus_in_guest.fetch_add(1, Ordering::Relaxed);
+
+        let exit_reason = self.run_bound_vcpu(&vcpu);
+
+        self.vcpus_in_guest.fetch_sub(1, Ordering::Relaxed);
+        vcpu.unbind()?;
+        exit_reason
+    }
 
+    /// Runs a bound vCPU and handles its VM exits,
+    /// until an exit that must be handled by the caller occurs.
+    fn run_bound_vcpu(&self, vcpu: &AxVCpuRef<U>) -> AxResult<AxVCpuExitReason> {
+        let vcpu_id = vcpu.id();
         let exit_reason = loop {
-            let exit_reason = vcpu.run()?;
-            trace!("{exit_reason:#x?}");
+            let run_start = H::current_time_nanos();
+            let exit_reason = vcpu.run();
+            self.inner_const.vcpu_guest_time_nanos[vcpu_id].fetch_add(
+                H::current_time_nanos().saturating_sub(run_start),
+                Ordering::Relaxed,
+            );
+            let exit_reason = exit_reason?;
+            trace!("[vm={} vcpu={}] {exit_reason:#x?}", self.id(), vcpu_id);
+            let exit_info = VmExitInfo::from(&exit_reason);
+            self.inner_const.vcpu_last_exits[vcpu_id].record(exit_info);
+            self.stats.record_exit(exit_info.kind);
             let handled = match &exit_reason {
                 AxVCpuExitReason::MmioRead {
                     addr,
@@ -260,36 +850,697 @@ impl<H: AxVMHal, U: AxVCpuHal> AxVM<H, U> {
                     reg,
                     reg_width: _,
                 } => {
-                    let val = self
-                        .get_devices()
-                        .handle_mmio_read(*addr, (*width).into())?;
+                    // The inline MMIO list is unlocked before falling back to emulated devices,
+                    // which must not be serialized on it.
+                    let inline_val = self
+                        .inner_mut
+                        .inline_mmio
+                        .lock()
+                        .iter_mut()
+                        .find(|r| r.range().contains(*addr))
+                        .map(|region| region.handle_read(*addr, (*width).into()));
+                    let val = match inline_val {
+                        Some(val) => val,
+                        None => self
+                            .get_devices()
+                            .handle_mmio_read(*addr, (*width).into())
+                            .inspect_err(|err| match self.mmio_region_for(*addr) {
+                                Some((dev_idx, ..)) => warn!(
+                                    "[vm={} vcpu={}] MMIO read fault at {:?} belongs to device {}: {:?}",
+                                    self.id(),
+                                    vcpu_id,
+                                    addr,
+                                    dev_idx,
+                                    err
+                                ),
+                                None => warn!(
+                                    "[vm={} vcpu={}] MMIO read fault at {:?}, no device: {:?}",
+                                    self.id(),
+                                    vcpu_id,
+                                    addr,
+                                    err
+                                ),
+                            })?,
+                    };
                     vcpu.set_gpr(*reg, val);
                     true
                 }
                 AxVCpuExitReason::MmioWrite { addr, width, data } => {
-                    self.get_devices()
-                        .handle_mmio_write(*addr, (*width).into(), *data as usize);
+                    let handled_inline = self
+                        .inner_mut
+                        .inline_mmio
+                        .lock()
+                        .iter_mut()
+                        .find(|r| r.range().contains(*addr))
+                        .map(|region| region.handle_write(*addr, (*width).into(), *data as usize))
+                        .is_some();
+                    if !handled_inline {
+                        self.get_devices().handle_mmio_write(
+                            *addr,
+                            (*width).into(),
+                            *data as usize,
+                        );
+                    }
+                    true
+                }
+                // A 32-bit `in` writes EAX, which zero-extends into RAX. 8- and 16-bit `in`
+                // only change AL/AX, and merging the value into RAX needs a way to read the
+                // guest's RAX, which `AxVCpu` does not provide yet. Such reads are returned
+                // to the caller to complete.
+                AxVCpuExitReason::IoRead { port, width } if usize::from(*width) == 4 => {
+                    let mut port_io = self.inner_mut.port_io.lock();
+                    let val = match port_io.iter_mut().find(|r| r.ports().contains(port)) {
+                        Some(region) => region.handle_read(*port, 4),
+                        // Reads from unhandled ports return all ones, like a floating bus.
+                        None => usize::MAX,
+                    };
+                    vcpu.set_gpr(0, val & u32::MAX as usize);
                     true
                 }
-                AxVCpuExitReason::IoRead { port: _, width: _ } => true,
-                AxVCpuExitReason::IoWrite {
-                    port: _,
-                    width: _,
-                    data: _,
-                } => true,
-                AxVCpuExitReason::NestedPageFault { addr, access_flags } => self
-                    .inner_mut
-                    .address_space
-                    .lock()
-                    .handle_page_fault(*addr, *access_flags),
+                AxVCpuExitReason::IoWrite { port, width, data } => {
+                    let mut port_io = self.inner_mut.port_io.lock();
+                    // Writes to unhandled ports are dropped.
+                    if let Some(region) = port_io.iter_mut().find(|r| r.ports().contains(port)) {
+                        region.handle_write(*port, (*width).into(), *data as usize);
+                    }
+                    true
+                }
+                AxVCpuExitReason::NestedPageFault { addr, access_flags } => {
+                    let mut addr_space = self.inner_mut.address_space.lock();
+                    let handled = if addr_space.translate(*addr).is_some() {
+                        // The page may have been populated since the fault was taken, by another
+                        // vCPU faulting on it or by a host access. Faulting it in again would leak
+                        // a frame, so just re-enter the guest if the access is permitted.
+                        self.region_flags(*addr)
+                            .is_some_and(|flags| flags.contains(*access_flags))
+                    } else {
+                        addr_space.handle_page_fault(*addr, *access_flags)
+                    };
+                    if handled {
+                        trace!(
+                            "[vm={} vcpu={}] Nested page fault at {:?} resolved",
+                            self.id(),
+                            vcpu_id,
+                            addr
+                        );
+                    } else {
+                        match self.region_flags(*addr) {
+                            Some(region_flags) if !region_flags.contains(*access_flags) => {
+                                warn!(
+                                    "[vm={} vcpu={}] Permission fault at {:?}: {:?} access to region with flags {:?}",
+                                    self.id(),
+                                    vcpu_id,
+                                    addr,
+                                    access_flags,
+                                    region_flags
+                                );
+                            }
+                            Some(_) => {
+                                // The access is permitted, but the page could not be populated,
+                                // e.g. because the host is out of memory.
+                                warn!(
+                                    "[vm={} vcpu={}] Failed to populate {:?} for {:?} access",
+                                    self.id(),
+                                    vcpu_id,
+                                    addr,
+                                    access_flags
+                                );
+                            }
+                            None if self.inner_const.config.trap_null_page()
+                                && addr.as_usize() < PAGE_SIZE_4K =>
+                            {
+                                warn!(
+                                    "[vm={} vcpu={}] Guest null pointer access at {:?}: {:?} access",
+                                    self.id(),
+                                    vcpu_id,
+                                    addr,
+                                    access_flags
+                                );
+                            }
+                            None => {
+                                warn!(
+                                    "[vm={} vcpu={}] Translation fault at unmapped {:?}: {:?} access",
+                                    self.id(),
+                                    vcpu_id,
+                                    addr,
+                                    access_flags
+                                );
+                            }
+                        }
+                    }
+                    handled
+                }
                 _ => false,
             };
             if !handled {
+                debug!(
+                    "[vm={} vcpu={}] Exit to caller: {exit_reason:x?}",
+                    self.id(),
+                    vcpu_id
+                );
                 break exit_reason;
             }
         };
-
-        vcpu.unbind()?;
         Ok(exit_reason)
     }
 }
+
+/// Asserts that every memory region is non-empty,
+/// and that eagerly populated ones are mapped in `addr_space`.
+#[cfg(any(test, feature = "debug"))]
+fn assert_memory_regions_mapped<P: PagingHandler>(
+    addr_space: &AddrSpace<P>,
+    memory_regions: &[VmMemConfig],
+) {
+    for region in memory_regions {
+        assert!(region.size != 0, "memory region {:x?} is empty", region);
+        if region.lazy {
+            continue;
+        }
+        let start = GuestPhysAddr::from(region.gpa);
+        let last = GuestPhysAddr::from(region.gpa + region.size - 1).align_down_4k();
+        for gpa in [start, last] {
+            assert!(
+                addr_space.translate(gpa).is_some(),
+                "memory region {:x?} is not mapped at {:?}",
+                region,
+                gpa
+            );
+        }
+    }
+}
+
+/// Asserts that inline MMIO regions overlap neither each other, emulated devices
+/// nor memory regions, and that emulated devices do not overlap memory regions.
+#[cfg(any(test, feature = "debug"))]
+fn assert_mmio_regions_disjoint(
+    memory_regions: &[VmMemConfig],
+    devices: &[AddrRange<GuestPhysAddr>],
+    inline_mmio: &[AddrRange<GuestPhysAddr>],
+) {
+    let memory = memory_regions
+        .iter()
+        .map(|r| AddrRange::from_start_size(GuestPhysAddr::from(r.gpa), r.size))
+        .collect::<Vec<_>>();
+    for &device in devices {
+        assert!(
+            memory.iter().all(|m| !m.overlaps(device)),
+            "emulated device {:?} overlaps a memory region",
+            device
+        );
+    }
+    for (i, &range) in inline_mmio.iter().enumerate() {
+        assert!(
+            inline_mmio[..i].iter().all(|r| !r.overlaps(range)),
+            "inline MMIO region {:?} overlaps another one",
+            range
+        );
+        assert!(
+            devices.iter().all(|d| !d.overlaps(range)),
+            "inline MMIO region {:?} overlaps an emulated device",
+            range
+        );
+        assert!(
+            memory.iter().all(|m| !m.overlaps(range)),
+            "inline MMIO region {:?} overlaps a memory region",
+            range
+        );
+    }
+}
+
+/// Unmaps `[gpa, gpa + size)`, which must lie within a single ram region of `memory_regions`,
+/// and splits that region around the resulting hole.
+fn unmap_ram_range<P: PagingHandler>(
+    addr_space: &mut AddrSpace<P>,
+    memory_regions: &mut Vec<VmMemConfig>,
+    gpa: GuestPhysAddr,
+    size: usize,
+) -> AxResult {
+    if size == 0 || !gpa.is_aligned_4k() || !memory_addr::is_aligned_4k(size) {
+        return ax_err!(
+            InvalidInput,
+            format!("Unaligned or empty memory range {:?}+{:#x}", gpa, size)
+        );
+    }
+    let start = gpa.as_usize();
+    let end = start.checked_add(size).ok_or_else(|| {
+        ax_err_type!(
+            InvalidInput,
+            format!("Memory range {:?}+{:#x} overflows", gpa, size)
+        )
+    })?;
+
+    let idx = memory_regions
+        .iter()
+        .position(|r| {
+            r.gpa <= start
+                && end <= r.gpa + r.size
+                && MappingFlags::from_bits(r.flags)
+                    .is_some_and(|flags| !flags.contains(MappingFlags::DEVICE))
+        })
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!(
+                    "Memory range {:?}+{:#x} is not within a single ram region",
+                    gpa, size
+                )
+            )
+        })?;
+    addr_space.unmap(gpa, size)?;
+
+    let region = memory_regions.remove(idx);
+    if end < region.gpa + region.size {
+        memory_regions.insert(
+            idx,
+            VmMemConfig {
+                gpa: end,
+                size: region.gpa + region.size - end,
+                ..region.clone()
+            },
+        );
+    }
+    if region.gpa < start {
+        memory_regions.insert(
+            idx,
+            VmMemConfig {
+                size: start - region.gpa,
+                ..region
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Returns the host buffers covering an image of `image_size` bytes to be loaded at `image_load_gpa`.
+/// Returns an error if the image is empty, larger than `max_image_size`,
+/// or not fully backed by guest memory.
+fn image_load_region<P: PagingHandler>(
+    addr_space: &mut AddrSpace<P>,
+    image_load_gpa: GuestPhysAddr,
+    image_size: usize,
+    max_image_size: Option<usize>,
+) -> AxResult<Vec<&'static mut [u8]>> {
+    if image_size == 0 {
+        return ax_err!(
+            InvalidInput,
+            format!("Empty image to be loaded at {:?}", image_load_gpa)
+        );
+    }
+    if let Some(max_image_size) = max_image_size {
+        if image_size > max_image_size {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "Image size {:#x} at {:?} exceeds the maximum image size {:#x}",
+                    image_size, image_load_gpa, max_image_size
+                )
+            );
+        }
+    }
+    translate_guest_range(addr_space, image_load_gpa, image_size).map_err(|_| {
+        ax_err_type!(
+            InvalidInput,
+            format!(
+                "Image of size {:#x} at {:?} is not fully backed by guest memory",
+                image_size, image_load_gpa
+            )
+        )
+    })
+}
+
+/// Copies guest memory starting at `gpa` into `buf`.
+/// Returns an error if any part of the range is not mapped.
+fn read_guest_bytes<P: PagingHandler>(
+    addr_space: &mut AddrSpace<P>,
+    gpa: GuestPhysAddr,
+    buf: &mut [u8],
+) -> AxResult {
+    let mut copied = 0;
+    for chunk in translate_guest_range(addr_space, gpa, buf.len())? {
+        buf[copied..copied + chunk.len()].copy_from_slice(chunk);
+        copied += chunk.len();
+    }
+    Ok(())
+}
+
+/// Copies `data` into guest memory starting at `gpa`.
+/// Returns an error, without writing anything, if any part of the range is not mapped.
+fn write_guest_bytes<P: PagingHandler>(
+    addr_space: &mut AddrSpace<P>,
+    gpa: GuestPhysAddr,
+    data: &[u8],
+) -> AxResult {
+    copy_to_chunks(translate_guest_range(addr_space, gpa, data.len())?, data);
+    Ok(())
+}
+
+/// Copies `data` into the host buffers `chunks`, which must cover exactly `data.len()` bytes.
+fn copy_to_chunks(chunks: Vec<&'static mut [u8]>, data: &[u8]) {
+    let mut copied = 0;
+    for chunk in chunks {
+        let len = chunk.len();
+        chunk.copy_from_slice(&data[copied..copied + len]);
+        copied += len;
+    }
+}
+
+/// Translates the guest range `[gpa, gpa + len)` into host buffers covering it entirely,
+/// populating the pages of lazy regions the guest has not touched yet.
+fn translate_guest_range<P: PagingHandler>(
+    addr_space: &mut AddrSpace<P>,
+    gpa: GuestPhysAddr,
+    len: usize,
+) -> AxResult<Vec<&'static mut [u8]>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    populate_guest_range(addr_space, gpa, len);
+    addr_space
+        .translated_byte_buffer(gpa, len)
+        .filter(|chunks| chunks.iter().map(|c| c.len()).sum::<usize>() == len)
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("Guest range {:?}..+{:#x} is not fully mapped", gpa, len)
+            )
+        })
+}
+
+/// Populates the pages of lazy regions in `[gpa, gpa + len)` that the guest has not touched yet,
+/// so that the host can access them.
+///
+/// Pages outside of lazy regions are left as they are, so an unmapped page is still
+/// reported by the translation which follows.
+fn populate_guest_range<P: PagingHandler>(
+    addr_space: &mut AddrSpace<P>,
+    gpa: GuestPhysAddr,
+    len: usize,
+) {
+    for page in gpa_pages(gpa, len) {
+        // Faulting in a page which is already mapped would leak the newly allocated frame.
+        if addr_space.translate(page).is_none() {
+            // The host accesses the page, so no guest access permission is requested.
+            addr_space.handle_page_fault(page, MappingFlags::empty());
+        }
+    }
+}
+
+/// Checks that `gpa` is suitably aligned to hold a value of type `T`.
+fn check_guest_alignment<T>(gpa: GuestPhysAddr) -> AxResult {
+    if gpa.is_aligned(align_of::<T>()) {
+        Ok(())
+    } else {
+        ax_err!(
+            InvalidInput,
+            format!(
+                "Guest address {:?} is not aligned to {} bytes",
+                gpa,
+                align_of::<T>()
+            )
+        )
+    }
+}
+
+/// Trims the first page off a memory region starting at GPA 0,
+/// so that a guest null pointer access takes a nested page fault.
+/// Returns `None` if nothing is left to map.
+fn skip_null_page(mut region: VmMemConfig) -> Option<VmMemConfig> {
+    if region.gpa == 0 {
+        region.gpa = PAGE_SIZE_4K;
+        region.size = region.size.saturating_sub(PAGE_SIZE_4K);
+    }
+    (region.size != 0).then_some(region)
+}
+
+/// Maps a memory region into the VM's address space according to its flags.
+fn map_memory_region<P: PagingHandler>(
+    address_space: &mut AddrSpace<P>,
+    region: &VmMemConfig,
+) -> AxResult {
+    let mapping_flags = MappingFlags::from_bits(region.flags)
+        .ok_or_else(|| ax_err_type!(InvalidInput, format!("Illegal flags {:?}", region.flags)))?;
+    let gpa = GuestPhysAddr::from(region.gpa);
+
+    // Handle passthrough device's memory region.
+    // Todo: Perhaps we can merge the management of passthrough device memory
+    //       into the device configuration file.
+    if mapping_flags.contains(MappingFlags::DEVICE) {
+        address_space.map_linear(
+            gpa,
+            HostPhysAddr::from(region.gpa),
+            region.size,
+            mapping_flags,
+        )
+    } else {
+        // Handle ram region.
+        // Note: currently we use `map_alloc`,
+        // which allocates real physical memory in units of physical page frames,
+        // which may not be contiguous!!!
+        // Lazy regions are populated page by page in `handle_page_fault`.
+        address_space.map_alloc(gpa, region.size, mapping_flags, !region.lazy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use axerrno::AxError;
+    use core::cell::Cell;
+    use memory_addr::{PhysAddr, VirtAddr};
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+    /// Backs guest memory with host heap pages, identity-mapping "physical" addresses.
+    struct HeapPagingHandler;
+
+    std::thread_local! {
+        /// The number of frames currently allocated by the test running on this thread.
+        static ALLOCATED_FRAMES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn allocated_frames() -> usize {
+        ALLOCATED_FRAMES.with(Cell::get)
+    }
+
+    fn frame_layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE_4K, PAGE_SIZE_4K).unwrap()
+    }
+
+    impl PagingHandler for HeapPagingHandler {
+        fn alloc_frame() -> Option<PhysAddr> {
+            let frame = unsafe { alloc_zeroed(frame_layout()) };
+            if frame.is_null() {
+                return None;
+            }
+            ALLOCATED_FRAMES.with(|n| n.set(n.get() + 1));
+            Some(PhysAddr::from(frame as usize))
+        }
+
+        fn dealloc_frame(paddr: PhysAddr) {
+            ALLOCATED_FRAMES.with(|n| n.set(n.get() - 1));
+            unsafe { dealloc(paddr.as_usize() as *mut u8, frame_layout()) }
+        }
+
+        fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+            VirtAddr::from(paddr.as_usize())
+        }
+    }
+
+    const RAM_GPA: usize = 0x8000_0000;
+    const RAM_SIZE: usize = 0x4000;
+
+    fn ram_region(lazy: bool) -> VmMemConfig {
+        VmMemConfig {
+            gpa: RAM_GPA,
+            size: RAM_SIZE,
+            flags: (MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE).bits(),
+            lazy,
+        }
+    }
+
+    fn new_addr_space(regions: &[VmMemConfig]) -> AddrSpace<HeapPagingHandler> {
+        let mut addr_space =
+            AddrSpace::new_empty(GuestPhysAddr::from(VM_ASPACE_BASE), VM_ASPACE_SIZE).unwrap();
+        for region in regions {
+            map_memory_region(&mut addr_space, region).unwrap();
+        }
+        assert_memory_regions_mapped(&addr_space, regions);
+        addr_space
+    }
+
+    #[test]
+    fn image_load_region_within_ram() {
+        let mut addr_space = new_addr_space(&[ram_region(false)]);
+        let chunks = image_load_region(
+            &mut addr_space,
+            GuestPhysAddr::from(RAM_GPA),
+            RAM_SIZE,
+            None,
+        )
+        .unwrap();
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), RAM_SIZE);
+    }
+
+    #[test]
+    fn image_load_region_rejects_bad_images() {
+        let mut addr_space = new_addr_space(&[ram_region(false)]);
+        let gpa = GuestPhysAddr::from(RAM_GPA);
+        // Empty image.
+        assert_eq!(
+            image_load_region(&mut addr_space, gpa, 0, None).err(),
+            Some(AxError::InvalidInput)
+        );
+        // Image larger than the configured maximum.
+        assert_eq!(
+            image_load_region(&mut addr_space, gpa, 0x2000, Some(0x1000)).err(),
+            Some(AxError::InvalidInput)
+        );
+        // Image overrunning its region.
+        assert_eq!(
+            image_load_region(&mut addr_space, gpa + 0x1000, RAM_SIZE, None).err(),
+            Some(AxError::InvalidInput)
+        );
+        // Image at an unmapped address.
+        assert_eq!(
+            image_load_region(&mut addr_space, GuestPhysAddr::from(0x1000), 0x1000, None).err(),
+            Some(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn unmap_ram_range_punches_hole() {
+        let mut memory_regions = alloc::vec![ram_region(false)];
+        let mut addr_space = new_addr_space(&memory_regions);
+        let hole = GuestPhysAddr::from(RAM_GPA + PAGE_SIZE_4K);
+        unmap_ram_range(&mut addr_space, &mut memory_regions, hole, PAGE_SIZE_4K).unwrap();
+        assert_memory_regions_mapped(&addr_space, &memory_regions);
+
+        assert!(addr_space.translate(hole).is_none());
+        assert!(addr_space.translate(hole - PAGE_SIZE_4K).is_some());
+        assert!(addr_space.translate(hole + PAGE_SIZE_4K).is_some());
+        let layout: Vec<_> = memory_regions.iter().map(|r| (r.gpa, r.size)).collect();
+        assert_eq!(
+            layout,
+            [
+                (RAM_GPA, PAGE_SIZE_4K),
+                (RAM_GPA + 2 * PAGE_SIZE_4K, RAM_SIZE - 2 * PAGE_SIZE_4K)
+            ]
+        );
+    }
+
+    #[test]
+    fn unmap_ram_range_rejects_bad_ranges() {
+        let mut memory_regions = alloc::vec![ram_region(false)];
+        let mut addr_space = new_addr_space(&memory_regions);
+        let gpa = GuestPhysAddr::from(RAM_GPA);
+        for (gpa, size) in [
+            (gpa, 0),
+            (gpa + 1, PAGE_SIZE_4K),
+            (gpa, PAGE_SIZE_4K + 1),
+            (gpa + PAGE_SIZE_4K, RAM_SIZE),
+            (GuestPhysAddr::from(0x1000), PAGE_SIZE_4K),
+        ] {
+            assert_eq!(
+                unmap_ram_range(&mut addr_space, &mut memory_regions, gpa, size).err(),
+                Some(AxError::InvalidInput)
+            );
+        }
+        assert_eq!(memory_regions.len(), 1);
+        assert_memory_regions_mapped(&addr_space, &memory_regions);
+    }
+
+    #[test]
+    fn skip_null_page_trims_first_page() {
+        let region = |gpa, size| VmMemConfig {
+            gpa,
+            size,
+            ..Default::default()
+        };
+        let trimmed = skip_null_page(region(0, 0x4000)).unwrap();
+        assert_eq!((trimmed.gpa, trimmed.size), (PAGE_SIZE_4K, 0x3000));
+        assert!(skip_null_page(region(0, PAGE_SIZE_4K)).is_none());
+        let untouched = skip_null_page(region(RAM_GPA, 0x4000)).unwrap();
+        assert_eq!((untouched.gpa, untouched.size), (RAM_GPA, 0x4000));
+    }
+
+    #[test]
+    fn lazy_region_populates_touched_pages_only() {
+        const LAZY_SIZE: usize = 0x1000_0000;
+        let region = VmMemConfig {
+            size: LAZY_SIZE,
+            ..ram_region(true)
+        };
+        let base_frames = allocated_frames();
+        let mut addr_space = new_addr_space(&[region]);
+        // Only page tables are allocated, not the 64K frames an eager region would take.
+        assert!(allocated_frames() - base_frames < 8);
+        assert!(gpa_pages(GuestPhysAddr::from(RAM_GPA), LAZY_SIZE)
+            .step_by(0x100)
+            .all(|gpa| addr_space.translate(gpa).is_none()));
+
+        let first = GuestPhysAddr::from(RAM_GPA + 0x10_0000);
+        let mut buf = [0u8; 16];
+        read_guest_bytes(&mut addr_space, first, &mut buf).unwrap();
+        assert!(addr_space.translate(first).is_some());
+        assert!(addr_space.translate(first - PAGE_SIZE_4K).is_none());
+        assert!(addr_space.translate(first + PAGE_SIZE_4K).is_none());
+
+        // The page tables covering the second page already exist, so it takes exactly one frame,
+        // and touching populated pages again takes none.
+        let second = first + PAGE_SIZE_4K;
+        let frames = allocated_frames();
+        write_guest_bytes(&mut addr_space, second, &buf).unwrap();
+        assert_eq!(allocated_frames(), frames + 1);
+        populate_guest_range(&mut addr_space, first, 2 * PAGE_SIZE_4K);
+        assert_eq!(allocated_frames(), frames + 1);
+        assert!(addr_space.translate(second + PAGE_SIZE_4K).is_none());
+    }
+
+    fn mmio_range(start: usize, size: usize) -> AddrRange<GuestPhysAddr> {
+        AddrRange::from_start_size(GuestPhysAddr::from(start), size)
+    }
+
+    #[test]
+    fn mmio_regions_disjoint() {
+        assert_mmio_regions_disjoint(
+            &[ram_region(false)],
+            &[mmio_range(0x900_0000, 0x1000)],
+            &[
+                mmio_range(0xa00_0000, 0x1000),
+                mmio_range(0xa00_1000, DOORBELL_REG_SIZE),
+            ],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps a memory region")]
+    fn inline_mmio_over_memory() {
+        assert_mmio_regions_disjoint(
+            &[ram_region(false)],
+            &[],
+            &[mmio_range(RAM_GPA + RAM_SIZE - 4, DOORBELL_REG_SIZE)],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps an emulated device")]
+    fn inline_mmio_over_device() {
+        assert_mmio_regions_disjoint(
+            &[],
+            &[mmio_range(0x900_0000, 0x1000)],
+            &[mmio_range(0x900_0800, 0x1000)],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "emulated device")]
+    fn device_over_hot_added_memory() {
+        let hot_added = VmMemConfig {
+            gpa: 0x900_0000,
+            ..ram_region(false)
+        };
+        assert_mmio_regions_disjoint(&[hot_added], &[mmio_range(0x900_1000, 0x1000)], &[]);
+    }
+}